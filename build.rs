@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::process::Command;
+
+// Bakes the commit and build time into the binary for `GET /version`.
+// Builds outside a git checkout (or without git installed) still succeed and
+// report "unknown"; CI can pin either value through the env vars below.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT_HASH");
+    println!("cargo:rerun-if-env-changed=BUILD_TIMESTAMP");
+    // Only watch paths that exist: cargo reruns the script on every build
+    // when a watched path is missing.
+    for path in [".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    let commit = std::env::var("GIT_COMMIT_HASH")
+        .ok()
+        .or_else(git_commit_hash)
+        .unwrap_or_else(|| "unknown".to_string());
+    let timestamp = std::env::var("BUILD_TIMESTAMP")
+        .unwrap_or_else(|_| chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+}
+
+fn git_commit_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?;
+    let hash = hash.trim();
+    (!hash.is_empty()).then(|| hash.to_string())
+}
@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+/// `0.1.0+1a2b3c4d5e6f`: tells apart builds of the same crate version.
+pub fn service_version() -> String {
+    format!("{}+{}", VERSION, GIT_COMMIT)
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub build_timestamp: &'static str,
+    pub environment: String,
+}
+
+impl BuildInfo {
+    pub fn new(environment: String) -> Self {
+        Self {
+            version: VERSION,
+            commit: GIT_COMMIT,
+            build_timestamp: BUILD_TIMESTAMP,
+            environment,
+        }
+    }
+}
@@ -1,4 +1,10 @@
+pub mod build_info;
 pub mod configuration;
+pub mod maintenance;
+pub mod reload;
+pub mod request_id;
+pub mod request_meta;
 pub mod routes;
 pub mod startup;
 pub mod telemetry;
+pub mod utils;
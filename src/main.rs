@@ -1,19 +1,56 @@
-use secrecy::ExposeSecret;
-use sqlx::PgPool;
-use std::net::TcpListener;
-use zer02prod::{configuration::get_configuration, startup::run, telemetry::*};
+use zer02prod::configuration::{get_configuration, LogFormat};
+#[cfg(unix)]
+use zer02prod::reload::reload_on_sighup;
+use zer02prod::{startup::Application, telemetry::*};
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
+    // Telemetry is set up from the configuration, so warnings raised while
+    // reading it go through a plain stdout subscriber
+    let config = tracing::subscriber::with_default(
+        get_subscriber(
+            "zero2prod".into(),
+            "unknown".into(),
+            "info".into(),
+            LogFormat::default(),
+            std::io::stdout,
+            None,
+            LogSampling::default(),
+        ),
+        get_configuration,
+    )
+    .expect("Failed to read configuration");
+    let tracer_provider = config
+        .telemetry_settings
+        .otlp
+        .as_ref()
+        .map(get_otlp_tracer_provider)
+        .transpose()
+        .expect("Failed to set up the OTLP exporter");
+    let _sentry = config
+        .telemetry_settings
+        .sentry
+        .as_ref()
+        .map(|settings| init_sentry(settings, &config.application_settings.environment));
     init_subscriber(get_subscriber(
         "zero2prod".into(),
-        "info".into(),
+        config.application_settings.environment.clone(),
+        config.telemetry_settings.filter_directives(),
+        config.telemetry_settings.format,
         std::io::stdout,
+        tracer_provider.as_ref().map(get_tracer),
+        LogSampling::new(config.telemetry_settings.sample.clone()),
     ));
-    let config = get_configuration().expect("Failed to read configuration");
-    let address = format!("127.0.0.1:{}", config.application_port);
-    let listener = TcpListener::bind(address)?;
-    let database_pool = PgPool::connect_lazy(&config.database_settings.connection_string().expose_secret())
-        .expect("Couldn't get database connection");
-    run(listener, database_pool)?.await
+
+    let application = Application::build(config).await?;
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(application.settings_reloader()));
+    let outcome = application.run_until_stopped().await;
+
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(err) = tracer_provider.shutdown() {
+            tracing::error!("Failed to flush the OTLP exporter: {}", err);
+        }
+    }
+    outcome
 }
@@ -0,0 +1,40 @@
+use crate::configuration::DynamicSettings;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{ContentType, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use tokio::sync::watch;
+
+const RETRY_AFTER_SECS: u64 = 300;
+
+const MAINTENANCE_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>Down for maintenance</title></head>
+<body>
+<h1>Down for maintenance</h1>
+<p>We are making some changes. Please try again in a few minutes.</p>
+</body>
+</html>
+"#;
+
+/// Answers public routes with a 503 instead of letting them hit a database
+/// that may be mid-migration. Maintenance mode is looked up per request so
+/// a configuration reload takes effect immediately.
+pub async fn refuse_during_maintenance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let in_maintenance = req
+        .app_data::<web::Data<watch::Receiver<DynamicSettings>>>()
+        .is_some_and(|settings| settings.borrow().maintenance_mode);
+    if !in_maintenance {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let response = HttpResponse::ServiceUnavailable()
+        .content_type(ContentType::html())
+        .insert_header((RETRY_AFTER, RETRY_AFTER_SECS))
+        .body(MAINTENANCE_PAGE);
+    Ok(req.into_response(response).map_into_right_body())
+}
@@ -0,0 +1,66 @@
+use crate::configuration::{get_configuration, ConfigurationErrors, DynamicSettings, Settings};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Publishes new `DynamicSettings` to the running server. Handlers and
+/// middleware read the current values through a `watch::Receiver`.
+#[derive(Clone)]
+pub struct SettingsReloader {
+    sender: Arc<watch::Sender<DynamicSettings>>,
+}
+
+impl SettingsReloader {
+    pub fn new(initial: DynamicSettings) -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(initial)),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<DynamicSettings> {
+        self.sender.subscribe()
+    }
+
+    pub fn current(&self) -> DynamicSettings {
+        self.sender.borrow().clone()
+    }
+
+    /// Swaps in the dynamic part of `settings`. Invalid settings are refused
+    /// and the current values stay in place; static settings are ignored.
+    pub fn apply(&self, settings: &Settings) -> Result<(), ConfigurationErrors> {
+        settings.validate()?;
+        let new = DynamicSettings::from(settings);
+        let changes = self.sender.borrow().diff(&new);
+        if changes.is_empty() {
+            tracing::info!("Configuration reloaded, no reloadable setting changed");
+        }
+        for change in changes {
+            tracing::info!("Configuration reloaded: {}", change);
+        }
+        self.sender.send_replace(new);
+        Ok(())
+    }
+
+    /// Re-reads the configuration sources and applies the result.
+    pub fn reload(&self) -> Result<(), config::ConfigError> {
+        let settings = get_configuration()?;
+        self.apply(&settings)
+            .map_err(|err| config::ConfigError::Message(err.to_string()))
+    }
+}
+
+#[cfg(unix)]
+pub async fn reload_on_sighup(reloader: SettingsReloader) -> Result<(), std::io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading configuration");
+        if let Err(err) = reloader.reload() {
+            tracing::error!(
+                "Refusing to reload configuration, keeping the current settings: {}",
+                err
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use crate::request_meta::ClientIp;
+use crate::telemetry::PiiRedaction;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, USER_AGENT};
+use actix_web::http::Version;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use std::borrow::Cow;
+use std::future::{ready, Ready};
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accepts caller-supplied ids only if they are short tokens that are
+    /// safe to echo back in a header and to write into logs.
+    pub fn parse(s: &str) -> Option<Self> {
+        let is_valid = !s.is_empty()
+            && s.len() <= MAX_REQUEST_ID_LENGTH
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        is_valid.then(|| Self(s.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let request_id = req.extensions().get::<RequestId>().cloned().ok_or_else(|| {
+            actix_web::error::ErrorInternalServerError(
+                "The request id middleware is not registered",
+            )
+        });
+        ready(request_id)
+    }
+}
+
+pub async fn propagate_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(request_id.clone());
+
+    // Handler errors have already been turned into responses at this point,
+    // so `ResponseError` output gets the header as well.
+    let mut response = next.call(req).await?;
+    response.headers_mut().insert(
+        HeaderName::from_static(REQUEST_ID_HEADER),
+        HeaderValue::from_str(request_id.as_str()).expect("Request ids are valid header values"),
+    );
+    Ok(response)
+}
+
+/// Builds the request root span with the fields `root_span!` sets, but with
+/// `request_id` holding the id echoed in `X-Request-Id` rather than
+/// tracing-actix-web's own, which callers never see, and `http.client_ip`
+/// resolved through the trusted proxies instead of taken from whatever
+/// `X-Forwarded-For` the client sent, and masked per `PiiRedaction`. The
+/// span is built by hand because the `[HTTP REQUEST - START]` line is
+/// written as it opens, before a `Span::record` could replace the value.
+pub struct RequestIdRootSpanBuilder;
+
+impl RootSpanBuilder for RequestIdRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        let client_ip = ClientIp::of(request.request());
+        let redaction = request
+            .app_data::<web::Data<PiiRedaction>>()
+            .map(|redaction| *redaction.get_ref())
+            .unwrap_or_default();
+        let connection_info = request.connection_info();
+        let method = request.method().as_str();
+        let route = request.match_pattern().unwrap_or_else(|| "default".into());
+        let user_agent = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let span = tracing::info_span!(
+            "HTTP request",
+            http.method = %method,
+            http.route = %route,
+            http.flavor = %http_flavor(request.version()),
+            http.scheme = %connection_info.scheme(),
+            http.host = %connection_info.host(),
+            http.client_ip = %redaction.client_ip(client_ip),
+            http.user_agent = %user_agent,
+            http.target = %request.uri().path_and_query().map(|p| p.as_str()).unwrap_or(""),
+            http.status_code = Empty,
+            http.request.duration_ms = Empty,
+            otel.name = %format!("{} {}", method, route),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            trace_id = Empty,
+            request_id = %request_id,
+            exception.message = Empty,
+            exception.details = Empty,
+        );
+        if redaction.log_unredacted() {
+            span.in_scope(|| tracing::debug!(client_ip = %client_ip, "Unredacted client address"));
+        }
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+fn http_flavor(version: Version) -> Cow<'static, str> {
+    match version {
+        Version::HTTP_09 => "0.9".into(),
+        Version::HTTP_10 => "1.0".into(),
+        Version::HTTP_11 => "1.1".into(),
+        Version::HTTP_2 => "2.0".into(),
+        Version::HTTP_3 => "3.0".into(),
+        other => format!("{:?}", other).into(),
+    }
+}
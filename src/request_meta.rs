@@ -0,0 +1,91 @@
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use ipnet::IpNet;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(pub Vec<IpNet>);
+
+impl TrustedProxies {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// Works out the client address from the socket peer and the
+    /// `X-Forwarded-For` chain. The chain is walked from the right and only
+    /// for as long as every hop so far is trusted, so a client cannot spoof
+    /// its address by sending the header itself.
+    ///
+    /// A `None` peer means the connection came in over the Unix socket,
+    /// which only local processes can reach; it counts as a trusted hop as
+    /// long as any proxies are trusted at all.
+    pub fn resolve(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        if self.0.is_empty() {
+            return peer;
+        }
+        if let Some(peer) = peer {
+            if !self.contains(&peer) {
+                return Some(peer);
+            }
+        }
+
+        let mut client = peer;
+        let hops = forwarded_for.into_iter().flat_map(|h| h.rsplit(','));
+        for hop in hops {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) if self.contains(&ip) => client = Some(ip),
+                Ok(ip) => return Some(ip),
+                // Garbage from a trusted proxy: stick with the last hop we
+                // could vouch for rather than guessing further left
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+/// The address of the client that made the request, as far as it can be
+/// established through the configured trusted proxies. `None` only for
+/// Unix socket connections without a usable `X-Forwarded-For` chain.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(ip) => ip.fmt(f),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+impl ClientIp {
+    pub fn of(req: &HttpRequest) -> Self {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        // Repeated headers form a single list, in order
+        let forwarded_for = req
+            .headers()
+            .get_all(FORWARDED_FOR_HEADER)
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        let forwarded_for = (!forwarded_for.is_empty()).then_some(forwarded_for.as_str());
+        let ip = match req.app_data::<web::Data<TrustedProxies>>() {
+            Some(trusted) => trusted.resolve(peer, forwarded_for),
+            None => peer,
+        };
+        ClientIp(ip)
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(ClientIp::of(req)))
+    }
+}
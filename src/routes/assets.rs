@@ -0,0 +1,9 @@
+use actix_files::NamedFile;
+use actix_web::web;
+use std::path::PathBuf;
+
+pub struct StaticDir(pub PathBuf);
+
+pub async fn favicon(static_dir: web::Data<StaticDir>) -> actix_web::Result<NamedFile> {
+    Ok(NamedFile::open_async(static_dir.0.join("favicon.ico")).await?)
+}
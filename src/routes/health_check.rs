@@ -1,5 +1,142 @@
-use actix_web::{HttpResponse, Responder};
+use crate::configuration::DynamicSettings;
+use crate::startup::pending_migrations;
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const DEPENDENCY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyStatus {
+    Ok,
+    Failing,
+}
+
+#[derive(Serialize)]
+pub struct LivenessReport {
+    status: DependencyStatus,
+}
+
+#[derive(Serialize)]
+pub struct DeepHealthReport {
+    status: DependencyStatus,
+    database: DependencyStatus,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessReport {
+    status: DependencyStatus,
+    database: DependencyStatus,
+    migrations: DependencyStatus,
+    maintenance: DependencyStatus,
+}
 
 pub async fn health_check() -> impl Responder {
     HttpResponse::Ok()
 }
+
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(LivenessReport {
+        status: DependencyStatus::Ok,
+    })
+}
+
+#[tracing::instrument(name = "Running deep health check", skip(pool))]
+pub async fn deep_health_check(pool: web::Data<PgPool>) -> HttpResponse {
+    let database = check_database(pool.get_ref()).await;
+    let status = if database == DependencyStatus::Ok {
+        DependencyStatus::Ok
+    } else {
+        DependencyStatus::Failing
+    };
+    let report = DeepHealthReport { status, database };
+    match report.status {
+        DependencyStatus::Ok => HttpResponse::Ok().json(report),
+        DependencyStatus::Failing => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+/// The deep checks, plus everything else that has to hold before the
+/// instance should get traffic.
+#[tracing::instrument(name = "Running readiness check", skip(pool, dynamic_settings))]
+pub async fn readiness(
+    pool: web::Data<PgPool>,
+    dynamic_settings: web::Data<watch::Receiver<DynamicSettings>>,
+) -> HttpResponse {
+    let (database, migrations) = tokio::join!(
+        check_database(pool.get_ref()),
+        check_migrations(pool.get_ref())
+    );
+    // Read per request, so a reload into maintenance mode drains traffic
+    let maintenance = if dynamic_settings.borrow().maintenance_mode {
+        DependencyStatus::Failing
+    } else {
+        DependencyStatus::Ok
+    };
+    let status = if [&database, &migrations, &maintenance]
+        .iter()
+        .all(|check| **check == DependencyStatus::Ok)
+    {
+        DependencyStatus::Ok
+    } else {
+        DependencyStatus::Failing
+    };
+    let report = ReadinessReport {
+        status,
+        database,
+        migrations,
+        maintenance,
+    };
+    match report.status {
+        DependencyStatus::Ok => HttpResponse::Ok().json(report),
+        DependencyStatus::Failing => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+#[tracing::instrument(name = "Checking database connectivity", skip(pool))]
+async fn check_database(pool: &PgPool) -> DependencyStatus {
+    match tokio::time::timeout(
+        DEPENDENCY_CHECK_TIMEOUT,
+        sqlx::query("SELECT 1").execute(pool),
+    )
+    .await
+    {
+        Ok(Ok(_)) => DependencyStatus::Ok,
+        Ok(Err(err)) => {
+            tracing::error!("Database health check failed: {}", err);
+            DependencyStatus::Failing
+        }
+        Err(_) => {
+            tracing::error!(
+                "Database health check timed out after {:?}",
+                DEPENDENCY_CHECK_TIMEOUT
+            );
+            DependencyStatus::Failing
+        }
+    }
+}
+
+#[tracing::instrument(name = "Checking database migrations", skip(pool))]
+async fn check_migrations(pool: &PgPool) -> DependencyStatus {
+    match tokio::time::timeout(DEPENDENCY_CHECK_TIMEOUT, pending_migrations(pool)).await {
+        Ok(Ok(pending)) if pending.is_empty() => DependencyStatus::Ok,
+        Ok(Ok(pending)) => {
+            tracing::error!("{} database migrations are not applied", pending.len());
+            DependencyStatus::Failing
+        }
+        Ok(Err(err)) => {
+            tracing::error!("Failed to read the applied migrations: {}", err);
+            DependencyStatus::Failing
+        }
+        Err(_) => {
+            tracing::error!(
+                "Migration check timed out after {:?}",
+                DEPENDENCY_CHECK_TIMEOUT
+            );
+            DependencyStatus::Failing
+        }
+    }
+}
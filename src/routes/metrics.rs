@@ -0,0 +1,10 @@
+use crate::telemetry::HttpMetrics;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse};
+
+pub async fn metrics(metrics: web::Data<HttpMetrics>) -> Result<HttpResponse, actix_web::Error> {
+    let body = metrics.render().map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
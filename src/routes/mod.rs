@@ -1,4 +1,10 @@
+mod assets;
+pub use assets::*;
 mod health_check;
 pub use health_check::*;
+mod metrics;
+pub use metrics::*;
 mod subscriptions;
 pub use subscriptions::*;
+mod version;
+pub use version::*;
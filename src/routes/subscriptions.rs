@@ -1,4 +1,6 @@
-use actix_web::{web, HttpResponse, Responder};
+use crate::telemetry::PiiRedaction;
+use crate::utils::error_chain_fmt;
+use actix_web::{web, HttpResponse, ResponseError};
 use chrono::Utc;
 use serde::Deserialize;
 use sqlx::PgPool;
@@ -12,35 +14,56 @@ pub struct FormData {
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool),
+    skip(form, pool, redaction),
     fields(
-        name = %form.name,
-        email = %form.email
+        name = %redaction.name(&form.name),
+        email = %redaction.email(&form.email)
     )
 )]
 pub async fn subscribe(
     form: web::Form<FormData>,
     pool: web::Data<PgPool>,
-) -> impl Responder {
-    match insert_subscriber(pool.get_ref(), &form.into_inner()).await
-    {
-        Ok(_) => {
-            HttpResponse::Ok()
-        },
-        Err(_) => {
-            HttpResponse::InternalServerError()
-        },
+    redaction: web::Data<PiiRedaction>,
+) -> Result<HttpResponse, SubscribeError> {
+    if redaction.log_unredacted() {
+        tracing::debug!(
+            // Not `name`/`email`, which the span's masked values would shadow
+            subscriber_name = %form.name,
+            subscriber_email = %form.email,
+            "Unredacted subscriber details"
+        );
     }
+    insert_subscriber(pool.get_ref(), &form.into_inner())
+        .await
+        .map_err(SubscribeError)?;
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[tracing::instrument(
-    name = "Saving new subscriber details in the db",
-    skip(pool, form)
-)]
-pub async fn insert_subscriber(
-    pool: &PgPool,
-    form: &FormData,
-) -> Result<(), sqlx::Error> {
+pub struct SubscribeError(sqlx::Error);
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to store the new subscriber")
+    }
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl std::error::Error for SubscribeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+// `TracingLogger` logs the `Debug` output of errors behind 5xx responses
+impl ResponseError for SubscribeError {}
+
+#[tracing::instrument(name = "Saving new subscriber details in the db", skip(pool, form))]
+pub async fn insert_subscriber(pool: &PgPool, form: &FormData) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
         INSERT INTO subscriptions (id, email, name, subscribed_at)
@@ -52,10 +75,6 @@ pub async fn insert_subscriber(
         Utc::now()
     )
     .execute(pool)
-    .await
-    .map_err(|err| {
-        tracing::error!("Failed to execute query: {}", err);
-        err
-    })?;
+    .await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,6 @@
+use crate::build_info::BuildInfo;
+use actix_web::{web, HttpResponse};
+
+pub async fn version(build_info: web::Data<BuildInfo>) -> HttpResponse {
+    HttpResponse::Ok().json(build_info.get_ref())
+}
@@ -1,21 +1,434 @@
-use crate::routes::{health_check, subscribe};
-use actix_web::dev::Server;
-use actix_web::{web, App, HttpServer};
+use crate::build_info::BuildInfo;
+use crate::configuration::{
+    ApplicationSettings, CorsSettings, DatabaseSettings, DynamicSettings, SecurityHeadersSettings,
+    Settings, TelemetrySettings,
+};
+use crate::maintenance::refuse_during_maintenance;
+use crate::reload::SettingsReloader;
+use crate::request_id::{propagate_request_id, RequestIdRootSpanBuilder};
+use crate::request_meta::TrustedProxies;
+use crate::routes::{
+    deep_health_check, favicon, health_check, liveness, metrics, readiness, subscribe, version,
+    StaticDir,
+};
+use crate::telemetry::{record_http_metrics, HttpMetrics, PiiRedaction};
+use actix_cors::Cors;
+use actix_files::Files;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Server, ServiceRequest, ServiceResponse};
+use actix_web::error::{InternalError, JsonPayloadError, UrlencodedError};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::{from_fn, Compress, Condition, DefaultHeaders, Next};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, ResponseError};
+use sqlx::migrate::{MigrateError, Migration, Migrator};
 use sqlx::PgPool;
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::{fs::FileTypeExt, fs::PermissionsExt, net::UnixListener};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
 use tracing_actix_web::TracingLogger;
-use std::net::TcpListener;
 
-pub fn run(listener: TcpListener, database_connection: PgPool) -> Result<Server, std::io::Error> {
+pub struct Application {
+    bound_address: BoundAddress,
+    server: Server,
+    settings_reloader: SettingsReloader,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for BoundAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundAddress::Tcp(addr) => write!(f, "http://{}", addr),
+            #[cfg(unix)]
+            BoundAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bound_address(&self) -> Result<BoundAddress, std::io::Error> {
+        match self {
+            Listener::Tcp(listener) => Ok(BoundAddress::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Unix listeners must be bound to a path",
+                    )
+                })?;
+                Ok(BoundAddress::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+/// Builds an `Application` from `Settings`, with hooks to swap in
+/// components built elsewhere (tests, alternative binaries) instead of the
+/// ones the settings describe.
+pub struct ApplicationBuilder {
+    settings: Settings,
+    db_pool: Option<PgPool>,
+    listener: Option<Listener>,
+}
+
+impl ApplicationBuilder {
+    /// Use this pool instead of connecting with `database_settings`.
+    pub fn with_db_pool(mut self, db_pool: PgPool) -> Self {
+        self.db_pool = Some(db_pool);
+        self
+    }
+
+    /// Serve on this listener instead of binding `port`/`socket_path`.
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub async fn build(self) -> Result<Application, std::io::Error> {
+        let settings = self.settings;
+        let database_pool = self
+            .db_pool
+            .unwrap_or_else(|| get_connection_pool(&settings.database_settings));
+        if settings.database_settings.migrate_on_startup {
+            run_migrations(&database_pool).await?;
+        }
+
+        let settings_reloader = SettingsReloader::new(DynamicSettings::from(&settings));
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => bind(&settings.application_settings)?,
+        };
+        let bound_address = listener.bound_address()?;
+        tracing::info!("Listening on {}", bound_address);
+        let server = run(
+            listener,
+            database_pool,
+            settings_reloader.subscribe(),
+            settings.application_settings,
+            settings.cors_settings,
+            settings.security_headers_settings,
+            settings.telemetry_settings,
+        )?;
+
+        Ok(Application {
+            bound_address,
+            server,
+            settings_reloader,
+        })
+    }
+}
+
+impl Application {
+    pub fn builder(settings: Settings) -> ApplicationBuilder {
+        ApplicationBuilder {
+            settings,
+            db_pool: None,
+            listener: None,
+        }
+    }
+
+    pub async fn build(settings: Settings) -> Result<Self, std::io::Error> {
+        Self::builder(settings).build().await
+    }
+
+    pub fn bound_address(&self) -> &BoundAddress {
+        &self.bound_address
+    }
+
+    pub fn settings_reloader(&self) -> SettingsReloader {
+        self.settings_reloader.clone()
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        self.server.await
+    }
+}
+
+fn bind(settings: &ApplicationSettings) -> Result<Listener, std::io::Error> {
+    #[cfg(unix)]
+    if let Some(socket_path) = &settings.socket_path {
+        tracing::warn!(
+            "Binding the Unix socket {}; application_settings.port ({}) is ignored",
+            socket_path.display(),
+            settings.port
+        );
+        return Ok(Listener::Unix(bind_unix_socket(socket_path)?));
+    }
+
+    Ok(Listener::Tcp(TcpListener::bind((
+        "127.0.0.1",
+        settings.port,
+    ))?))
+}
+
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> Result<UnixListener, std::io::Error> {
+    // A socket left behind by a previous run would make bind fail; anything
+    // that is not a socket is left alone (validation refuses such paths).
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(path)?;
+    // Owner and group (e.g. a shared nginx group) may connect, nobody else
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+pub fn get_connection_pool(settings: &DatabaseSettings) -> PgPool {
+    settings
+        .pool_options()
+        .connect_lazy_with(settings.with_db())
+}
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// The migrator holds a Postgres advisory lock while it runs, so replicas
+// starting at the same time apply each migration exactly once.
+#[tracing::instrument(name = "Running database migrations", skip(pool))]
+pub async fn run_migrations(pool: &PgPool) -> Result<(), std::io::Error> {
+    let err = match MIGRATOR.run(pool).await {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let context = match &err {
+        MigrateError::Execute(_) => match first_pending_migration(pool).await {
+            Some(name) => format!("Failed to apply migration {}", name),
+            None => "Failed to apply database migrations".to_string(),
+        },
+        _ => "Failed to apply database migrations".to_string(),
+    };
+    tracing::error!("{}: {}", context, err);
+    Err(std::io::Error::other(format!("{}: {}", context, err)))
+}
+
+// `MigrateError::Execute` also covers failing to connect or to take the
+// lock, so only blame a migration if we can tell which one is pending.
+async fn first_pending_migration(pool: &PgPool) -> Option<String> {
+    let pending = pending_migrations(pool).await.ok()?;
+    pending
+        .first()
+        .map(|migration| format!("{}_{}", migration.version, migration.description))
+}
+
+/// Migrations shipped with this binary that the database has not applied.
+/// Fails on a database that was never migrated, since there is no
+/// `_sqlx_migrations` table to read yet.
+pub(crate) async fn pending_migrations(
+    pool: &PgPool,
+) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+pub fn run(
+    listener: Listener,
+    database_connection: PgPool,
+    dynamic_settings: watch::Receiver<DynamicSettings>,
+    application_settings: ApplicationSettings,
+    cors_settings: CorsSettings,
+    security_headers_settings: SecurityHeadersSettings,
+    telemetry_settings: TelemetrySettings,
+) -> Result<Server, std::io::Error> {
     let connection = web::Data::new(database_connection);
-    let server = HttpServer::new(move || {
+    let payload_limit = application_settings.max_payload_bytes;
+    let compression = application_settings.compression;
+    let dynamic_settings = web::Data::new(dynamic_settings);
+    let static_dir = web::Data::new(StaticDir(application_settings.static_dir.clone()));
+    let build_info = web::Data::new(BuildInfo::new(application_settings.environment.clone()));
+    let pii_redaction = web::Data::new(PiiRedaction {
+        enabled: telemetry_settings.redact_pii,
+        debug_unredacted: application_settings.environment != "production",
+    });
+    let trusted_proxies =
+        web::Data::new(TrustedProxies(application_settings.trusted_proxies.clone()));
+    let http_metrics = web::Data::new(HttpMetrics::new().map_err(std::io::Error::other)?);
+    let serve_metrics = telemetry_settings.metrics;
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(TracingLogger::default())
+            .wrap(Condition::new(compression, Compress::default()))
+            .wrap(from_fn(record_http_metrics))
+            .wrap(TracingLogger::<RequestIdRootSpanBuilder>::new())
+            .wrap(from_fn(propagate_request_id))
+            .wrap(security_headers(&security_headers_settings))
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .route("/health_check/deep", web::get().to(deep_health_check))
+            .route("/health/live", web::get().to(liveness))
+            .route("/health/ready", web::get().to(readiness))
+            .route("/version", web::get().to(version))
+            .configure(|cfg| {
+                if serve_metrics {
+                    cfg.route("/metrics", web::get().to(metrics));
+                }
+            })
+            .service(
+                web::resource("/favicon.ico")
+                    .wrap(from_fn(cache_static_responses))
+                    .route(web::get().to(favicon)),
+            )
+            .service(
+                web::scope("/static")
+                    .wrap(from_fn(cache_static_responses))
+                    .service(Files::new("", &static_dir.0)),
+            )
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(from_fn(refuse_during_maintenance))
+                    .wrap(Condition::new(
+                        !cors_settings.allowed_origins.is_empty(),
+                        public_cors(&cors_settings),
+                    ))
+                    .route(web::post().to(subscribe)),
+            )
             .app_data(connection.clone())
-    })
-    .listen(listener)?
+            .app_data(static_dir.clone())
+            .app_data(build_info.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(pii_redaction.clone())
+            .app_data(dynamic_settings.clone())
+            .app_data(http_metrics.clone())
+            .app_data(
+                web::FormConfig::default()
+                    .limit(payload_limit)
+                    .error_handler(form_error_handler),
+            )
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(payload_limit)
+                    .error_handler(json_error_handler),
+            )
+            .app_data(web::PayloadConfig::new(payload_limit))
+    });
+    if let Some(workers) = application_settings.workers {
+        server = server.workers(workers.get());
+    }
+    if let Some(keep_alive_secs) = application_settings.keep_alive_secs {
+        server = server.keep_alive(Duration::from_secs(keep_alive_secs));
+    }
+    if let Some(timeout_ms) = application_settings.client_request_timeout_ms {
+        server = server.client_request_timeout(Duration::from_millis(timeout_ms));
+    }
+    let server = match listener {
+        Listener::Tcp(listener) => server.listen(listener)?,
+        #[cfg(unix)]
+        Listener::Unix(listener) => server.listen_uds(listener)?,
+    }
     .run();
 
     Ok(server)
 }
+
+// Only successful responses are cacheable: a 404 for a mistyped asset path
+// must not stick in browsers and CDNs for a day.
+async fn cache_static_responses(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut response = next.call(req).await?;
+    if response.status().is_success() {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=86400"),
+        );
+    }
+    Ok(response)
+}
+
+// `DefaultHeaders` runs after handler errors have been rendered, so
+// `ResponseError` output and 404s get these too.
+fn security_headers(settings: &SecurityHeadersSettings) -> DefaultHeaders {
+    let mut headers = DefaultHeaders::new();
+    if settings.content_type_options {
+        headers = headers.add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    }
+    if settings.frame_options {
+        headers = headers.add((header::X_FRAME_OPTIONS, "DENY"));
+    }
+    if let Some(policy) = &settings.referrer_policy {
+        headers = headers.add((header::REFERRER_POLICY, policy.as_str()));
+    }
+    if let Some(policy) = &settings.content_security_policy {
+        headers = headers.add((header::CONTENT_SECURITY_POLICY, policy.as_str()));
+    }
+    if let Some(max_age) = settings.strict_transport_security_max_age_secs {
+        headers = headers.add((
+            header::STRICT_TRANSPORT_SECURITY,
+            format!("max-age={}; includeSubDomains", max_age),
+        ));
+    }
+    headers
+}
+
+// Only the public, unauthenticated endpoints are meant to be called
+// cross-origin, so CORS is applied per resource rather than app-wide.
+fn public_cors(settings: &CorsSettings) -> Cors {
+    let cors = settings
+        .allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(["POST"])
+        .allowed_header(header::CONTENT_TYPE)
+        .max_age(settings.max_age_secs);
+    if settings.allow_credentials {
+        cors.supports_credentials()
+    } else {
+        cors
+    }
+}
+
+fn form_error_handler(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        UrlencodedError::Overflow { limit, .. } => payload_too_large(err, limit),
+        err => err.into(),
+    }
+}
+
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => payload_too_large(err, limit),
+        err => err.into(),
+    }
+}
+
+fn payload_too_large<E>(err: E, limit: usize) -> actix_web::Error
+where
+    E: ResponseError + 'static,
+{
+    let response = HttpResponse::PayloadTooLarge().json(serde_json::json!({
+        "error": "payload_too_large",
+        "message": err.to_string(),
+        "limit_bytes": limit,
+    }));
+    InternalError::from_response(err, response).into()
+}
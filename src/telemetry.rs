@@ -1,26 +1,616 @@
-use tracing::{subscriber::set_global_default, Subscriber};
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use crate::build_info;
+use crate::configuration::{LogFormat, OtlpSettings, SentrySettings};
+use crate::request_meta::ClientIp;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage};
+use ipnet::IpNet;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::trace::{Sampler, SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, TextEncoder};
+use rand::Rng;
+use secrecy::ExposeSecret;
+use sentry::integrations::tracing::{self as sentry_tracing, EventMapping, SentryLayer};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{subscriber::set_global_default, Event, Level, Metadata, Span, Subscriber};
+use tracing_actix_web::RootSpan;
+use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorage, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::{self, FmtContext, MakeWriter};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Registry};
 
+/// Every record carries `service.version`, `service.environment` and the
+/// hostname. `tracer`, when given, additionally exports every span through
+/// OpenTelemetry; the stdout output is the same either way. `sampling` only
+/// thins out the formatted output, exporters still see every request.
 pub fn get_subscriber<Sink>(
     name: String,
+    environment: String,
     env_filter: String,
+    format: LogFormat,
     sink: Sink,
-) -> impl Subscriber + Send + Sync
+    tracer: Option<SdkTracer>,
+    sampling: LogSampling,
+) -> Box<dyn Subscriber + Send + Sync>
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
-    Registry::default()
+    let otel_layer = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    // `JsonStorageLayer` collects span fields for Bunyan and for the request
+    // context attached to Sentry events, so it is there in every format
+    let subscriber = Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(otel_layer)
+        .with(sentry_layer())
+        .with(SamplingLayer { sampling });
+    let sink = SampledMakeWriter(sink);
+    let service = ServiceFields::new(environment);
+    match format {
+        LogFormat::Json => Box::new(subscriber.with(
+            // Bunyan records have a `hostname` of their own
+            BunyanFormattingLayer::with_default_fields(name, sink, service.default_fields()),
+        )),
+        LogFormat::Pretty => Box::new(
+            subscriber.with(
+                fmt::layer()
+                    .event_format(service.prefix(fmt::format().pretty()))
+                    .with_writer(sink),
+            ),
+        ),
+        LogFormat::Compact => Box::new(
+            subscriber.with(
+                fmt::layer()
+                    .event_format(service.prefix(fmt::format().compact()))
+                    .with_writer(sink),
+            ),
+        ),
+    }
+}
+
+/// What identifies the running service in every log record, so logs from
+/// two versions running side by side during a rollout can be told apart.
+struct ServiceFields {
+    version: String,
+    environment: String,
+    hostname: String,
+}
+
+impl ServiceFields {
+    fn new(environment: String) -> Self {
+        Self {
+            version: build_info::service_version(),
+            environment,
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+        }
+    }
+
+    fn default_fields(&self) -> HashMap<String, serde_json::Value> {
+        HashMap::from([
+            ("service.version".to_string(), self.version.clone().into()),
+            (
+                "service.environment".to_string(),
+                self.environment.clone().into(),
+            ),
+        ])
+    }
+
+    fn prefix<F>(&self, inner: F) -> WithServiceFields<F> {
+        WithServiceFields {
+            inner,
+            fields: format!(
+                "service.version={} service.environment={} hostname={}",
+                self.version, self.environment, self.hostname
+            ),
+        }
+    }
+}
+
+/// Writes the service fields in front of every event for the human
+/// readable formats.
+struct WithServiceFields<F> {
+    inner: F,
+    fields: String,
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithServiceFields<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        write!(writer, "{} ", self.fields)?;
+        self.inner.format_event(ctx, writer, event)
+    }
+}
+
+/// Route pattern to the share of its requests whose logs are written, as
+/// configured in `telemetry_settings.sample`. Routes without an entry are
+/// always logged.
+#[derive(Clone, Debug, Default)]
+pub struct LogSampling {
+    ratios: HashMap<String, f64>,
+}
+
+impl LogSampling {
+    pub fn new(ratios: HashMap<String, f64>) -> Self {
+        Self { ratios }
+    }
+
+    fn keep(&self, route: &str) -> bool {
+        match self.ratios.get(route) {
+            Some(&ratio) if ratio < 1.0 => rand::thread_rng().gen::<f64>() < ratio,
+            _ => true,
+        }
+    }
+}
+
+/// Holds back the log output of an unsampled request until it is known
+/// whether the request logged an error: if it did, the buffered lines are
+/// written out and the rest of the request is logged as usual, otherwise
+/// everything is dropped when the root span closes.
+#[derive(Default)]
+struct DeferredOutput {
+    buffer: Vec<u8>,
+    decision: Option<bool>,
+}
+
+type SharedOutput = Arc<Mutex<DeferredOutput>>;
+
+thread_local! {
+    // Set by `SamplingLayer` right before the formatting layer handles the
+    // same callback, and picked up by `SampledWriter`
+    static CURRENT_OUTPUT: RefCell<Option<SharedOutput>> = const { RefCell::new(None) };
+}
+
+/// Tracks which spans belong to an unsampled request. Only decides where
+/// output goes; it must sit below the formatting layer so its callbacks run
+/// first.
+struct SamplingLayer {
+    sampling: LogSampling,
+}
+
+impl<S> Layer<S> for SamplingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let inherited = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SharedOutput>().cloned());
+        let output = inherited.or_else(|| {
+            let mut route = RouteVisitor(None);
+            attrs.record(&mut route);
+            route
+                .0
+                .filter(|route| !self.sampling.keep(route))
+                .map(|_| SharedOutput::default())
+        });
+        if let Some(output) = &output {
+            span.extensions_mut().insert(output.clone());
+        }
+        CURRENT_OUTPUT.with(|current| *current.borrow_mut() = output);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let output = ctx
+            .event_span(event)
+            .and_then(|span| span.extensions().get::<SharedOutput>().cloned());
+        if let Some(output) = &output {
+            if *event.metadata().level() == Level::ERROR {
+                output.lock().unwrap().decision.get_or_insert(true);
+            }
+        }
+        CURRENT_OUTPUT.with(|current| *current.borrow_mut() = output);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).expect("Span not found, this is a bug");
+        let output = span.extensions().get::<SharedOutput>().cloned();
+        if let Some(output) = &output {
+            let is_request_root = span
+                .parent()
+                .is_none_or(|parent| parent.extensions().get::<SharedOutput>().is_none());
+            if is_request_root {
+                output.lock().unwrap().decision.get_or_insert(false);
+            }
+        }
+        CURRENT_OUTPUT.with(|current| *current.borrow_mut() = output);
+    }
+}
+
+/// Picks up the `http.route` field of request root spans.
+struct RouteVisitor(Option<String>);
+
+impl Visit for RouteVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "http.route" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "http.route" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+struct SampledMakeWriter<M>(M);
+
+impl<'a, M> MakeWriter<'a> for SampledMakeWriter<M>
+where
+    M: MakeWriter<'a>,
+{
+    type Writer = SampledWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SampledWriter {
+            inner: self.0.make_writer(),
+            output: CURRENT_OUTPUT.with(|current| current.borrow().clone()),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SampledWriter {
+            inner: self.0.make_writer_for(meta),
+            output: CURRENT_OUTPUT.with(|current| current.borrow().clone()),
+        }
+    }
+}
+
+struct SampledWriter<W> {
+    inner: W,
+    output: Option<SharedOutput>,
+}
+
+impl<W: Write> Write for SampledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(output) = &self.output else {
+            return self.inner.write(buf);
+        };
+        let mut output = output.lock().unwrap();
+        match output.decision {
+            None => output.buffer.extend_from_slice(buf),
+            Some(false) => {}
+            Some(true) => {
+                let held_back = std::mem::take(&mut output.buffer);
+                self.inner.write_all(&held_back)?;
+                return self.inner.write(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends `error!` events to Sentry and keeps `warn!`/`info!` events as
+/// breadcrumbs. Inert until `init_sentry` binds a client. Spans are not sent
+/// at all, since their fields are where request data (emails, names) lives.
+fn sentry_layer<S>() -> SentryLayer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    sentry_tracing::layer()
+        .span_filter(|_| false)
+        .event_mapper(|event, ctx| match *event.metadata().level() {
+            Level::ERROR => {
+                let mut sentry_event = sentry_tracing::event_from_event(event, &ctx);
+                add_request_context(&mut sentry_event, event, &ctx);
+                EventMapping::Event(sentry_event)
+            }
+            Level::WARN | Level::INFO => {
+                EventMapping::Breadcrumb(sentry_tracing::breadcrumb_from_event(event, &ctx))
+            }
+            _ => EventMapping::Ignore,
+        })
+}
+
+/// Tags the event with the request id, route and user of the request it
+/// happened in, as recorded on the enclosing spans.
+fn add_request_context<S>(
+    sentry_event: &mut sentry::protocol::Event<'static>,
+    event: &tracing::Event<'_>,
+    ctx: &Context<'_, S>,
+) where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span) = ctx.event_span(event) else {
+        return;
+    };
+    let extensions = span.extensions();
+    // Child spans inherit their parents' fields in the storage
+    let Some(storage) = extensions.get::<JsonStorage<'static>>() else {
+        return;
+    };
+    let tags = [
+        ("request_id", "request_id"),
+        ("http.route", "route"),
+        ("user_id", "user_id"),
+    ];
+    for (field, tag) in tags {
+        if let Some(value) = storage.values().get(field) {
+            let value = value
+                .as_str()
+                .map(str::to_owned)
+                .unwrap_or_else(|| value.to_string());
+            sentry_event.tags.insert(tag.into(), value);
+        }
+    }
+}
+
+pub fn sentry_client_options(
+    settings: &SentrySettings,
+    environment: &str,
+) -> sentry::ClientOptions {
+    sentry::ClientOptions {
+        dsn: settings.dsn.expose_secret().parse().ok(),
+        release: Some(format!("zero2prod@{}", build_info::service_version()).into()),
+        environment: Some(
+            settings
+                .environment
+                .clone()
+                .unwrap_or_else(|| environment.to_string())
+                .into(),
+        ),
+        sample_rate: settings.sample_rate,
+        send_default_pii: false,
+        max_request_body_size: sentry::MaxRequestBodySize::None,
+        ..Default::default()
+    }
+}
+
+/// Binds a Sentry client for the whole process, panics included. Keep the
+/// guard alive until exit: dropping it flushes queued events.
+pub fn init_sentry(settings: &SentrySettings, environment: &str) -> sentry::ClientInitGuard {
+    sentry::init(sentry_client_options(settings, environment))
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Builds the provider behind the OTLP exporter. Spans are batched on a
+/// background thread; call `shutdown` on the provider before exiting so the
+/// last batch is flushed.
+pub fn get_otlp_tracer_provider(
+    settings: &OtlpSettings,
+) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let headers = settings
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.expose_secret().to_string()))
+        .collect();
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&settings.endpoint)
+        .with_headers(headers)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            settings.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(settings.service_name.clone())
+                .with_attribute(KeyValue::new(
+                    "service.version",
+                    build_info::service_version(),
+                ))
+                .build(),
+        )
+        .build())
+}
+
+pub fn get_tracer(provider: &SdkTracerProvider) -> SdkTracer {
+    provider.tracer("zero2prod")
+}
+
+/// Decides how personal data is written into span fields. Handlers take it
+/// as `web::Data<PiiRedaction>` and route every such field through it.
+#[derive(Clone, Copy, Debug)]
+pub struct PiiRedaction {
+    pub enabled: bool,
+    // Outside production the full values are still logged at debug level
+    // while redaction is on, see `log_unredacted`
+    pub debug_unredacted: bool,
+}
+
+impl Default for PiiRedaction {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            debug_unredacted: false,
+        }
+    }
+}
+
+impl PiiRedaction {
+    /// Whether to also emit the full values in a debug event. Only ever true
+    /// when redaction is on, since they are in the span fields otherwise.
+    pub fn log_unredacted(&self) -> bool {
+        self.enabled && self.debug_unredacted
+    }
+
+    pub fn client_ip(&self, client_ip: ClientIp) -> String {
+        match client_ip.0 {
+            Some(ip) if self.enabled => redact_ip(ip),
+            _ => client_ip.to_string(),
+        }
+    }
+
+    pub fn email<'a>(&self, email: &'a str) -> Cow<'a, str> {
+        if self.enabled {
+            Cow::Owned(redact_email(email))
+        } else {
+            Cow::Borrowed(email)
+        }
+    }
+
+    pub fn name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.enabled {
+            Cow::Owned(redact_name(name))
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+}
+
+/// `ursula@example.com` becomes `u***@example.com`; the domain is kept since
+/// it is useful when chasing delivery problems.
+pub fn redact_email(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", redact_name(local), domain),
+        None => "***".to_string(),
+    }
+}
+
+/// Keeps the network only, /24 for IPv4 and /48 for IPv6: `203.0.113.7`
+/// becomes `203.0.113.0/24`. Still good enough to spot a misbehaving network.
+pub fn redact_ip(ip: IpAddr) -> String {
+    let prefix_len = match ip {
+        IpAddr::V4(_) => 24,
+        IpAddr::V6(_) => 48,
+    };
+    IpNet::new(ip, prefix_len)
+        .expect("The prefix length is valid for the address family")
+        .trunc()
+        .to_string()
+}
+
+/// Keeps the first character only: `le guin` becomes `l***`.
+pub fn redact_name(name: &str) -> String {
+    match name.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => String::new(),
+    }
+}
+
+/// Label used for requests that did not match any route, so probes for
+/// random paths cannot create new series.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Request latency and error counts per route. Routes are labelled with the
+/// actix match pattern (`/subscriptions/confirm`, `/items/{id}`), never the
+/// raw path, so tokens and ids in paths do not become label values.
+#[derive(Clone)]
+pub struct HttpMetrics {
+    registry: prometheus::Registry,
+    request_duration: HistogramVec,
+    request_errors: IntCounterVec,
+}
+
+impl HttpMetrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = prometheus::Registry::new();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests",
+            ),
+            &["route", "method"],
+        )?;
+        let request_errors = IntCounterVec::new(
+            Opts::new(
+                "http_request_errors_total",
+                "HTTP requests answered with a 4xx or 5xx status",
+            ),
+            &["route", "method", "status_class"],
+        )?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(request_errors.clone()))?;
+        Ok(Self {
+            registry,
+            request_duration,
+            request_errors,
+        })
+    }
+
+    pub fn registry(&self) -> &prometheus::Registry {
+        &self.registry
+    }
+
+    pub fn observe(&self, route: &str, method: &Method, status: StatusCode, elapsed: Duration) {
+        self.request_duration
+            .with_label_values(&[route, method.as_str()])
+            .observe(elapsed.as_secs_f64());
+        let status_class = if status.is_server_error() {
+            "5xx"
+        } else if status.is_client_error() {
+            "4xx"
+        } else {
+            return;
+        };
+        self.request_errors
+            .with_label_values(&[route, method.as_str(), status_class])
+            .inc();
+    }
+
+    /// The registry in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+/// Records every request into the `HttpMetrics` app data and its duration
+/// as `http.request.duration_ms` on the root span. Needs to sit inside
+/// `TracingLogger` so the root span exists, and runs after routing has
+/// happened so the match pattern is known.
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let metrics = req.app_data::<web::Data<HttpMetrics>>().cloned();
+    let root_span = req
+        .extensions()
+        .get::<RootSpan>()
+        .map(|span| Span::clone(span));
+    let method = req.method().clone();
+    let started = Instant::now();
+
+    let outcome = next.call(req).await;
+
+    let elapsed = started.elapsed();
+    if let Some(span) = root_span {
+        span.record("http.request.duration_ms", elapsed.as_millis() as u64);
+    }
+    if let Some(metrics) = metrics {
+        let (route, status) = match &outcome {
+            Ok(response) => (response.request().match_pattern(), response.status()),
+            Err(err) => (None, err.as_response_error().status_code()),
+        };
+        let route = route.as_deref().unwrap_or(UNMATCHED_ROUTE);
+        metrics.observe(route, &method, status, elapsed);
+    }
+    outcome
+}
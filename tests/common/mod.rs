@@ -0,0 +1,56 @@
+// Each test binary compiles its own copy and uses only part of it
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+use zer02prod::configuration::DatabaseSettings;
+
+// Keeps a copy of every log line so tests can assert on telemetry output
+#[derive(Clone, Default)]
+pub struct BufferWriter {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    echo: bool,
+}
+
+impl BufferWriter {
+    /// Also writes every line to stdout, e.g. when `TEST_LOG` is set.
+    pub fn echo_to_stdout(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for BufferWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.lock().unwrap().extend_from_slice(buf);
+        if self.echo {
+            std::io::stdout().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for BufferWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Points the settings at a database that refuses every connection, and
+/// gives up on it quickly.
+pub fn make_database_unreachable(database_settings: &mut DatabaseSettings) {
+    // Nothing listens on port 1
+    database_settings.port = 1;
+    database_settings.acquire_timeout_ms = 500;
+}
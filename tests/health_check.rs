@@ -1,26 +1,41 @@
+mod common;
+
+use common::{make_database_unreachable, BufferWriter};
+use flate2::read::GzDecoder;
 use once_cell::sync::Lazy;
-use secrecy::ExposeSecret;
-use sqlx::{Executor, PgPool};
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::io::Read;
 use std::net::TcpListener;
+use std::num::NonZeroUsize;
 use uuid::Uuid;
 use zer02prod::{
-    configuration::{get_configuration, DatabaseSettings},
-    telemetry::{get_subscriber, init_subscriber},
+    configuration::{get_configuration, DatabaseSettings, LogFormat, Settings},
+    startup::{get_connection_pool, Application, ApplicationBuilder, Listener},
+    telemetry::{get_subscriber, init_subscriber, LogSampling},
 };
 
+const ALLOWED_ORIGIN: &str = "https://allowed.example";
+
+static LOGS: Lazy<BufferWriter> =
+    Lazy::new(|| BufferWriter::default().echo_to_stdout(std::env::var("TEST_LOG").is_ok()));
+
 static TRACING: Lazy<()> = Lazy::new(|| {
-    match std::env::var("TEST_LOG") {
-        Ok(_) => {
-            let subscriber = get_subscriber("zero2prod".into(), "debug".into(), std::io::stdout);
-            init_subscriber(subscriber);
-        }
-        Err(_) => {
-            let subscriber = get_subscriber("zero2prod".into(), "debug".into(), std::io::sink);
-            init_subscriber(subscriber);
-        }
-    };
+    let subscriber = get_subscriber(
+        "zero2prod".into(),
+        "test".into(),
+        "debug".into(),
+        LogFormat::Json,
+        LOGS.clone(),
+        None,
+        LogSampling::default(),
+    );
+    init_subscriber(subscriber);
 });
 
+fn captured_logs() -> String {
+    LOGS.contents()
+}
+
 pub struct TestApp {
     address: String,
     db_pool: PgPool,
@@ -32,7 +47,7 @@ async fn health_check_works() {
     let client = reqwest::Client::new();
 
     let response = client
-        .get(&format!("{}/health_check", test_app.address))
+        .get(format!("{}/health_check", test_app.address))
         .send()
         .await
         .expect("Failed to execute request");
@@ -41,6 +56,1081 @@ async fn health_check_works() {
     assert_eq!(Some(0), response.content_length());
 }
 
+#[tokio::test]
+async fn deep_health_check_reports_healthy_dependencies() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health_check/deep", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["database"], "ok");
+}
+
+#[tokio::test]
+async fn deep_health_check_returns_503_when_database_is_down() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    make_database_unreachable(&mut configuration.database_settings);
+    let address = spawn_server(Application::builder(configuration)).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health_check/deep", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(503, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(body["status"], "failing");
+    assert_eq!(body["database"], "failing");
+}
+
+#[tokio::test]
+async fn liveness_returns_ok_status() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health/live", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(body, serde_json::json!({ "status": "ok" }));
+}
+
+#[tokio::test]
+async fn version_reports_build_information() {
+    let test_app = spawn_app_with(|c| c.application_settings.environment = "staging".into()).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/version", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(body["environment"], "staging");
+    assert!(!body["commit"].as_str().unwrap().is_empty());
+    assert!(!body["build_timestamp"].as_str().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn readiness_reports_healthy_dependencies() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health/ready", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "ok",
+            "database": "ok",
+            "migrations": "ok",
+            "maintenance": "ok"
+        })
+    );
+}
+
+#[tokio::test]
+async fn readiness_fails_on_an_unmigrated_database() {
+    Lazy::force(&TRACING);
+    let configuration = test_configuration();
+    create_database(&configuration.database_settings).await;
+    let address = spawn_server(Application::builder(configuration)).await;
+
+    let response = reqwest::get(format!("{}/health/ready", address))
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(503, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "failing",
+            "database": "ok",
+            "migrations": "failing",
+            "maintenance": "ok"
+        })
+    );
+}
+
+#[tokio::test]
+async fn readiness_fails_but_liveness_succeeds_when_database_is_down() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    make_database_unreachable(&mut configuration.database_settings);
+    let address = spawn_server(Application::builder(configuration)).await;
+    let client = reqwest::Client::new();
+
+    let ready = client
+        .get(format!("{}/health/ready", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let live = client
+        .get(format!("{}/health/live", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(503, ready.status().as_u16());
+    assert_eq!(200, live.status().as_u16());
+}
+
+#[tokio::test]
+async fn request_id_header_is_echoed_and_logged() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let request_id = format!("test-{}", Uuid::new_v4());
+
+    let response = client
+        .get(format!("{}/health_check", test_app.address))
+        .header("X-Request-Id", &request_id)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(
+        Some(request_id.as_str()),
+        response
+            .headers()
+            .get("X-Request-Id")
+            .and_then(|v| v.to_str().ok())
+    );
+    let logs = captured_logs();
+    let records: Vec<serde_json::Value> = logs
+        .lines()
+        .filter(|line| line.contains(&request_id))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(!records.is_empty(), "The request was not logged");
+    for record in records {
+        assert_eq!(request_id, record["request_id"], "{}", record);
+        assert!(record.get("http.request_id").is_none(), "{}", record);
+    }
+}
+
+#[tokio::test]
+async fn request_id_is_generated_when_missing_or_invalid() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for supplied in [None, Some("not a valid id\t")] {
+        let mut request = client.get(format!("{}/health_check", test_app.address));
+        if let Some(supplied) = supplied {
+            request = request.header("X-Request-Id", supplied);
+        }
+        let response = request.send().await.expect("Failed to execute request");
+
+        let request_id = response
+            .headers()
+            .get("X-Request-Id")
+            .expect("Missing X-Request-Id header")
+            .to_str()
+            .unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok());
+    }
+}
+
+#[tokio::test]
+async fn request_id_is_set_on_error_responses() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(response.headers().contains_key("X-Request-Id"));
+}
+
+#[tokio::test]
+async fn cors_preflight_from_allowed_origin_is_accepted() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/subscriptions", test_app.address),
+        )
+        .header("Origin", ALLOWED_ORIGIN)
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(response.status().is_success());
+    assert_eq!(
+        Some(ALLOWED_ORIGIN),
+        response
+            .headers()
+            .get("Access-Control-Allow-Origin")
+            .and_then(|v| v.to_str().ok())
+    );
+    assert!(response.headers().contains_key("Access-Control-Max-Age"));
+}
+
+#[tokio::test]
+async fn cors_preflight_from_disallowed_origin_gets_no_cors_headers() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/subscriptions", test_app.address),
+        )
+        .header("Origin", "https://evil.example")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(!response
+        .headers()
+        .contains_key("Access-Control-Allow-Origin"));
+}
+
+#[tokio::test]
+async fn cors_is_not_enabled_outside_public_routes() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health_check", test_app.address))
+        .header("Origin", ALLOWED_ORIGIN)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(!response
+        .headers()
+        .contains_key("Access-Control-Allow-Origin"));
+}
+
+#[tokio::test]
+async fn responses_are_gzip_compressed_when_accepted() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let plain = client
+        .get(format!("{}/health/ready", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert!(!plain.headers().contains_key("Content-Encoding"));
+    let plain_body = plain.bytes().await.unwrap();
+
+    let compressed = client
+        .get(format!("{}/health/ready", test_app.address))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(
+        Some("gzip"),
+        compressed
+            .headers()
+            .get("Content-Encoding")
+            .and_then(|v| v.to_str().ok())
+    );
+    let compressed_body = compressed.bytes().await.unwrap();
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&compressed_body[..])
+        .read_to_end(&mut decompressed)
+        .expect("Body was not valid gzip");
+
+    assert_eq!(plain_body, decompressed);
+}
+
+#[tokio::test]
+async fn subscribe_over_payload_limit_returns_413() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let limit = get_configuration()
+        .expect("Couldn't read configuration file")
+        .application_settings
+        .max_payload_bytes;
+
+    let prefix = "email=ursula_le_guin%40gmail.com&name=";
+    let body = format!("{}{}", prefix, "a".repeat(limit + 1 - prefix.len()));
+    let response = client
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(413, response.status().as_u16());
+    let body: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(body["error"], "payload_too_large");
+    assert_eq!(body["limit_bytes"], limit);
+}
+
+#[tokio::test]
+async fn static_assets_are_served_with_cache_headers() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/static/style.css", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let content_type = response.headers()["Content-Type"].to_str().unwrap();
+    assert!(content_type.starts_with("text/css"));
+    assert!(response.headers().contains_key("Cache-Control"));
+}
+
+#[tokio::test]
+async fn favicon_is_served() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/favicon.ico", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn static_path_traversal_is_rejected() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for path in ["%2e%2e/configuration.yaml", "..%2fconfiguration.yaml"] {
+        let response = client
+            .get(format!("{}/static/{}", test_app.address, path))
+            .send()
+            .await
+            .expect("Failed to execute request");
+
+        assert!(
+            response.status().is_client_error(),
+            "Traversal via {} was not rejected",
+            path
+        );
+        assert!(!response.text().await.unwrap().contains("database_settings"));
+    }
+}
+
+#[tokio::test]
+async fn missing_static_assets_are_not_cacheable() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/static/no-such-file.css", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(404, response.status().as_u16());
+    assert!(!response.headers().contains_key("Cache-Control"));
+}
+
+#[tokio::test]
+async fn server_serves_with_a_single_worker() {
+    let test_app = spawn_app_with(|settings| {
+        settings.application_settings.workers = NonZeroUsize::new(1);
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/health_check", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(response.status().is_success());
+}
+
+#[tokio::test]
+async fn startup_migrations_prepare_an_empty_database() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    configuration.database_settings.migrate_on_startup = true;
+    create_database(&configuration.database_settings).await;
+    let mut replica_configuration = test_configuration();
+    replica_configuration.database_settings.migrate_on_startup = true;
+    replica_configuration.database_settings.database_name =
+        configuration.database_settings.database_name.clone();
+
+    // Simulate two replicas booting at the same time
+    let (address, replica_address) = tokio::join!(
+        spawn_server(Application::builder(configuration)),
+        spawn_server(Application::builder(replica_configuration))
+    );
+    let replica_health = reqwest::get(format!("{}/health/ready", replica_address))
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, replica_health.status().as_u16());
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn startup_migrations_do_not_blame_a_migration_when_the_database_is_down() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    configuration.database_settings.migrate_on_startup = true;
+    make_database_unreachable(&mut configuration.database_settings);
+
+    let err = match Application::build(configuration).await {
+        Ok(_) => panic!("Startup succeeded without a database"),
+        Err(err) => err.to_string(),
+    };
+
+    assert!(
+        err.starts_with("Failed to apply database migrations"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn queries_fail_on_an_unmigrated_database() {
+    Lazy::force(&TRACING);
+    let configuration = test_configuration();
+    create_database(&configuration.database_settings).await;
+
+    let address = spawn_server(Application::builder(configuration)).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(500, response.status().as_u16());
+    assert_security_headers(&response);
+}
+
+#[tokio::test]
+async fn metrics_are_labelled_with_the_route_pattern() {
+    let test_app = spawn_app_with(|settings| settings.telemetry_settings.metrics = true).await;
+    let client = reqwest::Client::new();
+    let token = Uuid::new_v4();
+
+    for path in [format!("/static/{}.css", token), format!("/{}", token)] {
+        client
+            .get(format!("{}{}", test_app.address, path))
+            .send()
+            .await
+            .expect("Failed to execute request");
+    }
+    let metrics = client
+        .get(format!("{}/metrics", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .text()
+        .await
+        .unwrap();
+
+    assert!(!metrics.contains(&token.to_string()), "{}", metrics);
+    assert!(metrics.contains(r#"route="/static"#), "{}", metrics);
+    assert!(
+        metrics.contains(
+            r#"http_request_errors_total{method="GET",route="unmatched",status_class="4xx"} 1"#
+        ),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn metrics_are_not_served_unless_enabled() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/metrics", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn server_errors_are_counted_per_route() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    configuration.telemetry_settings.metrics = true;
+    create_database(&configuration.database_settings).await;
+    let address = spawn_server(Application::builder(configuration)).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/subscriptions", address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(500, response.status().as_u16());
+    let metrics = client
+        .get(format!("{}/metrics", address))
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .text()
+        .await
+        .unwrap();
+
+    assert!(
+        metrics.contains(r#"http_request_errors_total{method="POST",route="/subscriptions",status_class="5xx"} 1"#),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains(
+            r#"http_request_duration_seconds_count{method="POST",route="/subscriptions"} 1"#
+        ),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn server_errors_are_logged_once_with_their_cause() {
+    let test_app = spawn_app().await;
+    sqlx::query("DROP TABLE subscriptions")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    let request_id = format!("test-{}", Uuid::new_v4());
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", &request_id)
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(500, response.status().as_u16());
+
+    let errors: Vec<serde_json::Value> = captured_logs()
+        .lines()
+        .filter(|line| line.contains(&request_id))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|record: &serde_json::Value| record["level"] == 50)
+        .collect();
+    assert_eq!(1, errors.len(), "{:?}", errors);
+    let msg = errors[0]["msg"].as_str().unwrap();
+    assert!(
+        msg.contains(r#"relation "subscriptions" does not exist"#),
+        "{}",
+        msg
+    );
+}
+
+fn assert_security_headers(response: &reqwest::Response) {
+    let headers = response.headers();
+    assert_eq!("nosniff", headers["X-Content-Type-Options"]);
+    assert_eq!("DENY", headers["X-Frame-Options"]);
+    assert_eq!("no-referrer", headers["Referrer-Policy"]);
+    assert!(headers.contains_key("Content-Security-Policy"));
+}
+
+#[tokio::test]
+async fn security_headers_are_set_on_success_and_error_responses() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let ok = client
+        .get(format!("{}/health_check", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let not_found = client
+        .get(format!("{}/does-not-exist", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let bad_request = client
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, ok.status().as_u16());
+    assert_eq!(404, not_found.status().as_u16());
+    assert_eq!(400, bad_request.status().as_u16());
+    for response in [&ok, &not_found, &bad_request] {
+        assert_security_headers(response);
+        assert!(!response.headers().contains_key("Strict-Transport-Security"));
+    }
+}
+
+#[tokio::test]
+async fn security_headers_follow_configuration() {
+    let test_app = spawn_app_with(|settings| {
+        let headers = &mut settings.security_headers_settings;
+        headers.frame_options = false;
+        headers.content_security_policy = None;
+        headers.strict_transport_security_max_age_secs = Some(31_536_000);
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    let headers = response.headers();
+    assert!(!headers.contains_key("X-Frame-Options"));
+    assert!(!headers.contains_key("Content-Security-Policy"));
+    assert_eq!("nosniff", headers["X-Content-Type-Options"]);
+    assert_eq!(
+        "max-age=31536000; includeSubDomains",
+        headers["Strict-Transport-Security"]
+    );
+}
+
+#[tokio::test]
+async fn maintenance_mode_refuses_subscriptions_but_keeps_health_checks() {
+    let test_app = spawn_app_with(|settings| {
+        settings.application_settings.maintenance_mode = true;
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let subscribe = client
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let live = client
+        .get(format!("{}/health/live", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let ready = client
+        .get(format!("{}/health/ready", test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(503, subscribe.status().as_u16());
+    assert!(subscribe.headers().contains_key("Retry-After"));
+    assert!(subscribe.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    assert_eq!(200, live.status().as_u16());
+    assert_eq!(503, ready.status().as_u16());
+    let report: serde_json::Value =
+        serde_json::from_str(&ready.text().await.unwrap()).expect("Body was not JSON");
+    assert_eq!(report["maintenance"], "failing");
+    assert_eq!(report["database"], "ok");
+    let saved: Option<String> = sqlx::query_scalar("SELECT email FROM subscriptions")
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn maintenance_mode_can_be_toggled_by_a_reload() {
+    Lazy::force(&TRACING);
+    let mut configuration = test_configuration();
+    configure_database(&configuration.database_settings).await;
+    configuration.application_settings.port = 0;
+    // Only the dynamic settings of what gets reloaded are used
+    let mut configuration_for_reload = test_configuration();
+    let application = Application::build(configuration)
+        .await
+        .expect("Failed to build application");
+    let address = application.bound_address().to_string();
+    let reloader = application.settings_reloader();
+    tokio::spawn(application.run_until_stopped());
+    let subscribe = || async {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!(
+                "name=le%20guin&email={}%40example.com",
+                Uuid::new_v4()
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .status()
+            .as_u16()
+    };
+    assert_eq!(200, subscribe().await);
+
+    configuration_for_reload
+        .application_settings
+        .maintenance_mode = true;
+    reloader
+        .apply(&configuration_for_reload)
+        .expect("Failed to reload");
+    assert_eq!(503, subscribe().await);
+    let ready = reqwest::get(format!("{}/health/ready", address))
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(503, ready.status().as_u16());
+
+    // Invalid settings are refused wholesale, maintenance mode stays on
+    configuration_for_reload
+        .application_settings
+        .maintenance_mode = false;
+    configuration_for_reload
+        .application_settings
+        .max_payload_bytes = 0;
+    assert!(reloader.apply(&configuration_for_reload).is_err());
+    assert!(reloader.current().maintenance_mode);
+    assert_eq!(503, subscribe().await);
+
+    configuration_for_reload
+        .application_settings
+        .max_payload_bytes = 16_384;
+    reloader
+        .apply(&configuration_for_reload)
+        .expect("Failed to reload");
+    assert_eq!(200, subscribe().await);
+}
+
+#[tokio::test]
+async fn app_works_with_a_single_database_connection() {
+    let test_app = spawn_app_with(|settings| {
+        settings.database_settings.max_connections = 1;
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..3 {
+        let response = client
+            .get(format!("{}/health/ready", test_app.address))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = client
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, response.status().as_u16());
+}
+
+async fn logged_client_ip(test_app: &TestApp, forwarded_for: &str) -> String {
+    let request_id = format!("test-{}", Uuid::new_v4());
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Forwarded-For", forwarded_for)
+        .header("X-Request-Id", &request_id)
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, response.status().as_u16());
+
+    // Every line of the request carries the root span's address
+    let logs = captured_logs();
+    let client_ips: Vec<String> = logs
+        .lines()
+        .filter(|line| line.contains(&request_id))
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            record["http.client_ip"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert!(!client_ips.is_empty(), "The subscription was not logged");
+    assert!(
+        client_ips.iter().all(|ip| ip == &client_ips[0]),
+        "{:?}",
+        client_ips
+    );
+    client_ips[0].clone()
+}
+
+#[tokio::test]
+async fn request_log_lines_identify_the_service() {
+    let test_app = spawn_app().await;
+    let request_id = format!("test-{}", Uuid::new_v4());
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", test_app.address))
+        .header("X-Request-Id", &request_id)
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, response.status().as_u16());
+
+    let logs = captured_logs();
+    let line = logs
+        .lines()
+        .find(|line| line.contains(&request_id))
+        .expect("The request was not logged");
+    let line: serde_json::Value = serde_json::from_str(line).unwrap();
+    let version = line["service.version"].as_str().unwrap();
+    assert!(
+        version.starts_with(concat!(env!("CARGO_PKG_VERSION"), "+")),
+        "{}",
+        version
+    );
+    assert_eq!("test", line["service.environment"]);
+    assert!(!line["hostname"].as_str().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn statements_over_the_threshold_are_logged_as_slow() {
+    let test_app = spawn_app_with(|c| c.database_settings.slow_query_threshold_ms = 0).await;
+
+    let logs = subscribe_and_capture_logs(&test_app, "slow_query").await;
+
+    let slow_statement = logs
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|record| {
+            record["msg"]
+                .as_str()
+                .is_some_and(|msg| msg.contains("slow statement"))
+        })
+        .unwrap_or_else(|| panic!("No slow statement was logged: {}", logs));
+    assert_eq!(40, slow_statement["level"]);
+    assert!(slow_statement["summary"]
+        .as_str()
+        .unwrap()
+        .starts_with("INSERT INTO subscriptions"));
+}
+
+#[tokio::test]
+async fn statements_under_the_threshold_are_not_logged_as_slow() {
+    let test_app = spawn_app_with(|c| c.database_settings.slow_query_threshold_ms = 60_000).await;
+
+    let logs = subscribe_and_capture_logs(&test_app, "fast_query").await;
+
+    assert!(!logs.contains("slow statement"), "{}", logs);
+}
+
+async fn subscribe_and_capture_logs(test_app: &TestApp, local_part: &str) -> String {
+    let request_id = format!("test-{}", Uuid::new_v4());
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", &request_id)
+        .body(format!("name=le%20guin&email={}%40gmail.com", local_part))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, response.status().as_u16());
+
+    captured_logs()
+        .lines()
+        .filter(|line| line.contains(&request_id))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[tokio::test]
+async fn subscriber_pii_is_redacted_in_production_logs() {
+    let test_app = spawn_app_with(|settings| {
+        settings.application_settings.environment = "production".into();
+    })
+    .await;
+    let local_part = format!("redacted{}", Uuid::new_v4().simple());
+
+    let logs = subscribe_and_capture_logs(&test_app, &local_part).await;
+
+    assert!(logs.contains("r***@gmail.com"), "{}", logs);
+    assert!(!logs.contains(&local_part), "{}", logs);
+    assert!(!logs.contains("Unredacted"), "{}", logs);
+}
+
+#[tokio::test]
+async fn redacted_pii_is_logged_in_full_at_debug_outside_production() {
+    let test_app = spawn_app().await;
+    let local_part = format!("debug{}", Uuid::new_v4().simple());
+
+    let logs = subscribe_and_capture_logs(&test_app, &local_part).await;
+
+    let records: Vec<serde_json::Value> = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let unredacted = |msg: &str| {
+        records
+            .iter()
+            .find(|record| record["msg"].as_str().unwrap().ends_with(msg))
+            .unwrap_or_else(|| panic!("{:?} was not logged: {}", msg, logs))
+    };
+    let subscriber = unredacted("Unredacted subscriber details");
+    assert_eq!(20, subscriber["level"]);
+    assert_eq!(
+        format!("{}@gmail.com", local_part),
+        subscriber["subscriber_email"]
+    );
+    let client = unredacted("Unredacted client address");
+    assert_eq!(20, client["level"]);
+    assert_eq!("127.0.0.1", client["client_ip"]);
+    // Everything else keeps the masked values
+    assert!(logs.contains("d***@gmail.com"), "{}", logs);
+    assert!(
+        logs.contains(r#""http.client_ip":"127.0.0.0/24""#),
+        "{}",
+        logs
+    );
+}
+
+#[tokio::test]
+async fn subscriber_pii_is_logged_when_redaction_is_off() {
+    let test_app = spawn_app_with(|settings| settings.telemetry_settings.redact_pii = false).await;
+    let local_part = format!("visible{}", Uuid::new_v4().simple());
+
+    let logs = subscribe_and_capture_logs(&test_app, &local_part).await;
+
+    assert!(
+        logs.contains(&format!("{}@gmail.com", local_part)),
+        "{}",
+        logs
+    );
+    assert!(!logs.contains("Unredacted"), "{}", logs);
+}
+
+#[tokio::test]
+async fn client_ip_is_masked_when_redaction_is_on() {
+    let test_app = spawn_app_with(|settings| {
+        settings.application_settings.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+    })
+    .await;
+
+    let client_ip = logged_client_ip(&test_app, "2001:db8:1234:5678::1").await;
+
+    assert_eq!("2001:db8:1234::/48", client_ip);
+}
+
+#[tokio::test]
+async fn client_ip_is_taken_from_forwarded_for_behind_a_trusted_proxy() {
+    let test_app = spawn_app_with(|settings| {
+        settings.application_settings.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+        settings.telemetry_settings.redact_pii = false;
+    })
+    .await;
+
+    let client_ip = logged_client_ip(&test_app, "198.51.100.1, 203.0.113.7").await;
+
+    assert_eq!("203.0.113.7", client_ip);
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_without_trusted_proxies() {
+    let test_app = spawn_app_with(|settings| settings.telemetry_settings.redact_pii = false).await;
+
+    let client_ip = logged_client_ip(&test_app, "203.0.113.7").await;
+
+    assert_eq!("127.0.0.1", client_ip);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn server_can_listen_on_a_unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use zer02prod::startup::BoundAddress;
+
+    Lazy::force(&TRACING);
+    let socket_path = std::env::temp_dir().join(format!("zero2prod-{}.sock", Uuid::new_v4()));
+    // Leave a stale socket file behind, as a crashed previous run would
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+    let mut configuration = test_configuration();
+    configuration.application_settings.socket_path = Some(socket_path.clone());
+
+    let application = Application::build(configuration)
+        .await
+        .expect("Failed to build application");
+    assert_eq!(
+        &BoundAddress::Unix(socket_path.clone()),
+        application.bound_address()
+    );
+    tokio::spawn(application.run_until_stopped());
+
+    let mut stream = tokio::net::UnixStream::connect(&socket_path)
+        .await
+        .expect("Failed to connect to the socket");
+    stream
+        .write_all(b"GET /health_check HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let logs = captured_logs();
+    assert!(
+        logs.lines().any(|line| line.contains(&format!(
+            "Binding the Unix socket {}",
+            socket_path.display()
+        )) && line.contains("application_settings.port (8000) is ignored")),
+        "The ignored port was not reported"
+    );
+    std::fs::remove_file(&socket_path).ok();
+}
+
 #[tokio::test]
 async fn valid_subscribe_returns_200() {
     let test_app = spawn_app().await;
@@ -50,7 +1140,7 @@ async fn valid_subscribe_returns_200() {
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
 
     let response = client
-        .post(&format!("{}/subscriptions", test_app.address))
+        .post(format!("{}/subscriptions", test_app.address))
         .header("Content-Type", "application/x-www-form-urlencoded")
         .body(body)
         .send()
@@ -81,7 +1171,7 @@ async fn missing_data_subscribe_returns_400() {
 
     for (invalid_body, error_message) in test_cases {
         let response = client
-            .post(&format!("{}/subscriptions", test_app.address))
+            .post(format!("{}/subscriptions", test_app.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(invalid_body)
             .send()
@@ -98,35 +1188,44 @@ async fn missing_data_subscribe_returns_400() {
 }
 
 async fn spawn_app() -> TestApp {
-    Lazy::force(&TRACING);
+    spawn_app_with(|_| {}).await
+}
 
-    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind to random port");
-    let port = listener.local_addr().unwrap().port();
-    let address = format!("http://127.0.0.1:{}", port);
+async fn spawn_app_with(customize: impl FnOnce(&mut Settings)) -> TestApp {
+    Lazy::force(&TRACING);
 
-    let mut db_config = get_configuration()
-        .expect("Couldn't read configuration file")
-        .database_settings;
-    db_config.database_name = Uuid::new_v4().to_string();
-    let db_pool = configure_database(&db_config).await;
-    let server =
-        zer02prod::startup::run(listener, db_pool.clone()).expect("Failed to bind address");
-    let _ = tokio::spawn(server);
+    let mut configuration = test_configuration();
+    customize(&mut configuration);
+    let db_pool = configure_database(&configuration.database_settings).await;
+    let address =
+        spawn_server(Application::builder(configuration).with_db_pool(db_pool.clone())).await;
 
     TestApp { address, db_pool }
 }
 
-pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
-    PgPool::connect(&config.connection_string_without_db().expose_secret())
-        .await
-        .expect("Failed to connect to postgres")
-        .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
-        .await
-        .expect("Couldn't create a new test database");
+fn test_configuration() -> Settings {
+    let mut configuration = get_configuration().expect("Couldn't read configuration file");
+    configuration.database_settings.database_name = Uuid::new_v4().to_string();
+    configuration.cors_settings.allowed_origins = vec![ALLOWED_ORIGIN.into()];
+    configuration
+}
 
-    let connection_pool = PgPool::connect(&config.connection_string().expose_secret())
+async fn spawn_server(builder: ApplicationBuilder) -> String {
+    // Let the OS pick a free port so tests can run in parallel
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a random port");
+    let application = builder
+        .with_listener(Listener::Tcp(listener))
+        .build()
         .await
-        .expect("Failed connect to postgres");
+        .expect("Failed to build application");
+    let address = application.bound_address().to_string();
+    tokio::spawn(application.run_until_stopped());
+
+    address
+}
+
+pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
+    let connection_pool = create_database(config).await;
 
     sqlx::migrate!("./migrations")
         .run(&connection_pool)
@@ -135,3 +1234,14 @@ pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     connection_pool
 }
+
+async fn create_database(config: &DatabaseSettings) -> PgPool {
+    PgConnection::connect_with(&config.without_db())
+        .await
+        .expect("Failed to connect to postgres")
+        .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
+        .await
+        .expect("Couldn't create a new test database");
+
+    get_connection_pool(config)
+}
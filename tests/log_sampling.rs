@@ -0,0 +1,94 @@
+mod common;
+
+use common::{make_database_unreachable, BufferWriter};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use zer02prod::configuration::{get_configuration, LogFormat};
+use zer02prod::startup::{Application, Listener};
+use zer02prod::telemetry::{get_subscriber, init_subscriber, LogSampling};
+
+// The server logs from its own worker threads, so the subscriber has to be
+// the global one and this lives in its own test binary
+#[tokio::test]
+async fn unsampled_requests_are_only_logged_when_they_fail() {
+    let writer = BufferWriter::default();
+    let sampling = LogSampling::new(HashMap::from([
+        ("/health_check".to_string(), 0.0),
+        ("/subscriptions".to_string(), 0.0),
+    ]));
+    init_subscriber(get_subscriber(
+        "test".into(),
+        "test".into(),
+        "info".into(),
+        LogFormat::Json,
+        writer.clone(),
+        None,
+        sampling,
+    ));
+
+    // Inserting the subscription fails
+    let mut configuration = get_configuration().expect("Failed to read configuration");
+    make_database_unreachable(&mut configuration.database_settings);
+    let application = Application::builder(configuration)
+        .with_listener(Listener::Tcp(TcpListener::bind("127.0.0.1:0").unwrap()))
+        .build()
+        .await
+        .expect("Failed to build application");
+    let address = application.bound_address().to_string();
+    tokio::spawn(application.run_until_stopped());
+    let client = reqwest::Client::new();
+
+    for (path, request_id) in [
+        ("/health_check", "unsampled-health-check"),
+        ("/version", "sampled-version"),
+    ] {
+        let response = client
+            .get(format!("{}{}", address, path))
+            .header("X-Request-Id", request_id)
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = client
+        .post(format!("{}/subscriptions", address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", "unsampled-failure")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(500, response.status().as_u16());
+
+    let output = writer.contents();
+    let messages_for = |request_id: &str| -> Vec<String> {
+        output
+            .lines()
+            .filter(|line| line.contains(request_id))
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["msg"].as_str().unwrap().to_string()
+            })
+            .collect()
+    };
+    assert!(
+        messages_for("unsampled-health-check").is_empty(),
+        "{}",
+        output
+    );
+    assert!(!messages_for("sampled-version").is_empty(), "{}", output);
+    let failure = messages_for("unsampled-failure");
+    assert_eq!(
+        Some("[HTTP REQUEST - START]"),
+        failure.first().map(String::as_str)
+    );
+    assert!(
+        failure.iter().any(|msg| msg.contains("pool timed out")),
+        "{:?}",
+        failure
+    );
+    assert_eq!(
+        Some("[HTTP REQUEST - END]"),
+        failure.last().map(String::as_str)
+    );
+}
@@ -0,0 +1,68 @@
+use std::net::IpAddr;
+use zer02prod::request_meta::TrustedProxies;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn trusting(nets: &[&str]) -> TrustedProxies {
+    TrustedProxies(nets.iter().map(|net| net.parse().unwrap()).collect())
+}
+
+#[test]
+fn peer_is_used_when_no_proxies_are_trusted() {
+    let resolved = trusting(&[]).resolve(Some(ip("10.0.0.5")), Some("203.0.113.7"));
+
+    assert_eq!(Some(ip("10.0.0.5")), resolved);
+}
+
+#[test]
+fn forwarded_for_from_an_untrusted_peer_is_ignored() {
+    let resolved = trusting(&["10.0.0.0/8"]).resolve(Some(ip("198.51.100.9")), Some("203.0.113.7"));
+
+    assert_eq!(Some(ip("198.51.100.9")), resolved);
+}
+
+#[test]
+fn rightmost_untrusted_hop_is_the_client() {
+    let resolved = trusting(&["10.0.0.0/8"]).resolve(
+        Some(ip("10.0.0.1")),
+        Some("198.51.100.1, 203.0.113.7, 10.0.0.2"),
+    );
+
+    assert_eq!(Some(ip("203.0.113.7")), resolved);
+}
+
+#[test]
+fn spoofed_entries_left_of_the_client_are_ignored() {
+    // The client sent `X-Forwarded-For: 10.0.0.99` itself; our proxy
+    // appended the real address
+    let resolved =
+        trusting(&["10.0.0.0/8"]).resolve(Some(ip("10.0.0.1")), Some("10.0.0.99, 203.0.113.7"));
+
+    assert_eq!(Some(ip("203.0.113.7")), resolved);
+}
+
+#[test]
+fn unparseable_hop_stops_the_walk_at_the_last_trusted_hop() {
+    let resolved =
+        trusting(&["10.0.0.0/8"]).resolve(Some(ip("10.0.0.1")), Some("203.0.113.7, garbage"));
+
+    assert_eq!(Some(ip("10.0.0.1")), resolved);
+}
+
+#[test]
+fn trusted_peer_without_forwarded_for_is_the_client() {
+    let resolved = trusting(&["10.0.0.0/8"]).resolve(Some(ip("10.0.0.1")), None);
+
+    assert_eq!(Some(ip("10.0.0.1")), resolved);
+}
+
+#[test]
+fn unix_socket_peer_counts_as_trusted_only_when_proxies_are_configured() {
+    assert_eq!(
+        Some(ip("203.0.113.7")),
+        trusting(&["10.0.0.0/8"]).resolve(None, Some("203.0.113.7"))
+    );
+    assert_eq!(None, trusting(&[]).resolve(None, Some("203.0.113.7")));
+}
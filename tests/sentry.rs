@@ -0,0 +1,69 @@
+mod common;
+
+use common::make_database_unreachable;
+use sentry::test::TestTransport;
+use std::net::TcpListener;
+use std::sync::Arc;
+use zer02prod::configuration::{get_configuration, LogFormat, SentrySettings};
+use zer02prod::startup::{Application, Listener};
+use zer02prod::telemetry::{get_subscriber, init_subscriber, sentry_client_options, LogSampling};
+
+// A process-wide Sentry client, so this lives in its own test binary
+#[tokio::test]
+async fn errors_behind_a_500_are_reported_with_the_request_id() {
+    let transport = TestTransport::new();
+    let settings = SentrySettings {
+        dsn: "https://public@sentry.invalid/1".to_string().into(),
+        environment: None,
+        sample_rate: 1.0,
+    };
+    let mut options = sentry_client_options(&settings, "test");
+    options.transport = Some(Arc::new(transport.clone()));
+    let _sentry = sentry::init(options);
+    init_subscriber(get_subscriber(
+        "test".into(),
+        "test".into(),
+        "info".into(),
+        LogFormat::Json,
+        std::io::sink,
+        None,
+        LogSampling::default(),
+    ));
+
+    // Inserting the subscription fails
+    let mut configuration = get_configuration().expect("Failed to read configuration");
+    make_database_unreachable(&mut configuration.database_settings);
+    let application = Application::builder(configuration)
+        .with_listener(Listener::Tcp(TcpListener::bind("127.0.0.1:0").unwrap()))
+        .build()
+        .await
+        .expect("Failed to build application");
+    let address = application.bound_address().to_string();
+    tokio::spawn(application.run_until_stopped());
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", "sentry-test-request")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(500, response.status().as_u16());
+
+    let events = transport.fetch_and_clear_events();
+    assert_eq!(1, events.len(), "{:?}", events);
+    let event = &events[0];
+    assert_eq!(
+        Some("sentry-test-request"),
+        event.tags.get("request_id").map(String::as_str)
+    );
+    assert_eq!(
+        Some("/subscriptions"),
+        event.tags.get("route").map(String::as_str)
+    );
+    assert_eq!(Some("test"), event.environment.as_deref());
+    // Request data never leaves the process
+    let serialized = serde_json::to_string(event).unwrap();
+    assert!(!serialized.contains("ursula_le_guin"), "{}", serialized);
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use common::BufferWriter;
+use opentelemetry::Value;
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+use std::net::TcpListener;
+use zer02prod::configuration::{get_configuration, LogFormat, TelemetrySettings};
+use zer02prod::startup::{Application, Listener};
+use zer02prod::telemetry::{
+    get_subscriber, get_tracer, init_subscriber, redact_email, redact_ip, redact_name, LogSampling,
+};
+
+#[tokio::test]
+async fn request_spans_are_exported_with_their_route() {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    init_subscriber(get_subscriber(
+        "test".into(),
+        "test".into(),
+        "info".into(),
+        LogFormat::Json,
+        std::io::sink,
+        Some(get_tracer(&provider)),
+        LogSampling::default(),
+    ));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let configuration = get_configuration().expect("Failed to read configuration");
+    let application = Application::builder(configuration)
+        .with_listener(Listener::Tcp(listener))
+        .build()
+        .await
+        .expect("Failed to build application");
+    let address = application.bound_address().to_string();
+    tokio::spawn(application.run_until_stopped());
+
+    let response = reqwest::get(format!("{}/health_check", address))
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, response.status().as_u16());
+    provider.force_flush().unwrap();
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let request_span = spans
+        .iter()
+        .find(|span| {
+            span.attributes.iter().any(|attribute| {
+                attribute.key.as_str() == "http.route"
+                    && attribute.value == Value::from("/health_check")
+            })
+        })
+        .unwrap_or_else(|| panic!("No request span was exported: {:?}", spans));
+    assert!(request_span.parent_span_id == opentelemetry::trace::SpanId::INVALID);
+}
+
+fn first_log_line(format: LogFormat) -> String {
+    let writer = BufferWriter::default();
+    let subscriber = get_subscriber(
+        "test".into(),
+        "test".into(),
+        "info".into(),
+        format,
+        writer.clone(),
+        None,
+        LogSampling::default(),
+    );
+    tracing::subscriber::with_default(subscriber, || tracing::info!("Hello"));
+
+    let output = writer.contents();
+    output
+        .lines()
+        .next()
+        .expect("Nothing was logged")
+        .to_string()
+}
+
+#[test]
+fn json_format_writes_json_lines() {
+    let line = first_log_line(LogFormat::Json);
+
+    let record: serde_json::Value = serde_json::from_str(&line).expect("The line was not JSON");
+    assert_eq!("Hello", record["msg"]);
+    assert_eq!("test", record["service.environment"]);
+    assert!(record["service.version"].is_string());
+}
+
+#[test]
+fn pretty_and_compact_formats_are_not_json() {
+    for format in [LogFormat::Pretty, LogFormat::Compact] {
+        let line = first_log_line(format);
+
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&line).is_err(),
+            "{:?} wrote JSON: {}",
+            format,
+            line
+        );
+        assert!(
+            line.contains("service.environment=test"),
+            "{:?} has no service fields: {}",
+            format,
+            line
+        );
+    }
+}
+
+#[test]
+fn configured_filters_apply_per_target() {
+    let settings: TelemetrySettings = config::Config::builder()
+        .add_source(config::File::from_str(
+            r#"
+            level: "info"
+            filters:
+              sqlx::query: "warn"
+              zer02prod: "debug"
+            "#,
+            config::FileFormat::Yaml,
+        ))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .expect("Failed to parse settings");
+    let writer = BufferWriter::default();
+    let subscriber = get_subscriber(
+        "test".into(),
+        "test".into(),
+        settings.filter_directives(),
+        LogFormat::Json,
+        writer.clone(),
+        None,
+        LogSampling::default(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "sqlx::query", "SELECT 1");
+        tracing::debug!(target: "zer02prod::startup", "Our debug event");
+        tracing::debug!(target: "actix_server", "Someone else's debug event");
+    });
+
+    let output = writer.contents();
+    assert!(!output.contains("SELECT 1"), "{}", output);
+    assert!(output.contains("Our debug event"), "{}", output);
+    assert!(!output.contains("Someone else's debug event"), "{}", output);
+}
+
+#[test]
+fn errors_are_not_reported_without_a_sentry_client() {
+    let subscriber = get_subscriber(
+        "test".into(),
+        "test".into(),
+        "info".into(),
+        LogFormat::Json,
+        std::io::sink,
+        None,
+        LogSampling::default(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || tracing::error!("Boom"));
+
+    assert!(sentry::Hub::current().client().is_none());
+    assert!(sentry::last_event_id().is_none());
+}
+
+#[test]
+fn emails_and_names_are_masked() {
+    assert_eq!("u***@example.com", redact_email("ursula@example.com"));
+    assert_eq!("***", redact_email("not-an-email"));
+    assert_eq!("l***", redact_name("le guin"));
+    assert_eq!("É***", redact_name("Émile"));
+    assert_eq!("", redact_name(""));
+}
+
+#[test]
+fn ip_addresses_are_truncated_to_their_network() {
+    assert_eq!("203.0.113.0/24", redact_ip("203.0.113.7".parse().unwrap()));
+    assert_eq!(
+        "2001:db8:1234::/48",
+        redact_ip("2001:db8:1234:5678::1".parse().unwrap())
+    );
+}